@@ -0,0 +1,500 @@
+//! A minimal, read-only IMAP server exposing a `MailStore` as a single `INBOX`.
+//!
+//! This implements just enough of RFC 3501 for IMAP-speaking applications under test to be
+//! black-box tested against captured mail: `LOGIN`/`AUTHENTICATE` (any credentials are
+//! accepted), `SELECT INBOX`, `FETCH`/`UID FETCH` of `FLAGS`, `INTERNALDATE`, `ENVELOPE` and
+//! `BODY[]`/`RFC822`, and `SEARCH` on `FROM`/`TO`/`SUBJECT`/`SINCE`. There is no way to alter
+//! stored mail through this interface; message sequence numbers and UIDs are both derived from
+//! the timestamp ordering `MailStore::iter_mails` already produces.
+
+use crate::util::parse_address_list;
+use crate::{Mail, MailStore};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+
+/// Serve `store` as a read-only IMAP `INBOX` on `addr`, blocking forever.
+///
+/// Each client connection is handled on its own thread; connections never see mail added to the
+/// store after `SELECT` was issued, matching a plain, non-`IDLE` client's expectations.
+pub fn serve<A: ToSocketAddrs>(store: MailStore, addr: A) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let store = Arc::new(store);
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = Arc::clone(&store);
+        thread::spawn(move || {
+            if let Err(e) = handle_client(stream, store) {
+                eprintln!("trapmail serve-imap: client error: {}", e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// State tracked for a single connection once `SELECT INBOX` has succeeded.
+struct Mailbox {
+    /// Mails in the box, in sequence-number/UID order (index 0 is sequence 1 / UID 1).
+    mails: Vec<Mail>,
+}
+
+fn handle_client(stream: TcpStream, store: Arc<MailStore>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+
+    write!(writer, "* OK trapmail read-only IMAP server ready\r\n")?;
+
+    let mut mailbox: Option<Mailbox> = None;
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut words = line.splitn(3, ' ');
+        let tag = words.next().unwrap_or("*");
+        let command = words.next().unwrap_or("").to_ascii_uppercase();
+        let rest = words.next().unwrap_or("");
+
+        match command.as_str() {
+            "CAPABILITY" => {
+                write!(writer, "* CAPABILITY IMAP4rev1 AUTH=PLAIN\r\n")?;
+                write!(writer, "{} OK CAPABILITY completed\r\n", tag)?;
+            }
+            "LOGIN" | "AUTHENTICATE" => {
+                // Any credentials are accepted; `trapmail` is read-only test infrastructure.
+                write!(writer, "{} OK LOGIN completed\r\n", tag)?;
+            }
+            "SELECT" | "EXAMINE" if rest.trim().eq_ignore_ascii_case("INBOX") => {
+                let mails = collect_mails(&store)?;
+                write!(writer, "* {} EXISTS\r\n", mails.len())?;
+                write!(writer, "* 0 RECENT\r\n")?;
+                write!(writer, "* OK [UIDVALIDITY 1] UIDs valid\r\n")?;
+                write!(writer, "* OK [UIDNEXT {}] next UID\r\n", mails.len() + 1)?;
+                write!(writer, "* FLAGS (\\Seen)\r\n")?;
+                mailbox = Some(Mailbox { mails });
+                write!(writer, "{} OK [READ-ONLY] SELECT completed\r\n", tag)?;
+            }
+            "SELECT" | "EXAMINE" => {
+                write!(writer, "{} NO only INBOX exists\r\n", tag)?;
+            }
+            "FETCH" => fetch(&mut writer, tag, &mailbox, rest, false)?,
+            "UID" => {
+                let mut sub = rest.splitn(2, ' ');
+                match sub.next().unwrap_or("").to_ascii_uppercase().as_str() {
+                    "FETCH" => fetch(&mut writer, tag, &mailbox, sub.next().unwrap_or(""), true)?,
+                    "SEARCH" => search(&mut writer, tag, &mailbox, sub.next().unwrap_or(""), true)?,
+                    _ => write!(writer, "{} BAD unsupported UID subcommand\r\n", tag)?,
+                }
+            }
+            "SEARCH" => search(&mut writer, tag, &mailbox, rest, false)?,
+            "NOOP" => write!(writer, "{} OK NOOP completed\r\n", tag)?,
+            "LOGOUT" => {
+                write!(writer, "* BYE trapmail IMAP server logging out\r\n")?;
+                write!(writer, "{} OK LOGOUT completed\r\n", tag)?;
+                return Ok(());
+            }
+            _ => write!(writer, "{} BAD unsupported command\r\n", tag)?,
+        }
+        writer.flush()?;
+    }
+}
+
+/// Collect all mails from `store`, in the timestamp order `MailStore::iter_mails` already
+/// produces; this order defines both sequence numbers and UIDs.
+fn collect_mails(store: &MailStore) -> io::Result<Vec<Mail>> {
+    store
+        .iter_mails()
+        .map(|r| r.map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string())))
+        .collect()
+}
+
+/// Parse a sequence set (`1`, `1:3`, `1,3,5`, `1:*`) into the list of 1-based numbers it denotes,
+/// clamped to `max`.
+fn parse_seq_set(set: &str, max: usize) -> Vec<usize> {
+    let mut result = Vec::new();
+
+    for part in set.split(',') {
+        let part = part.trim();
+        if let Some((lo, hi)) = part.split_once(':') {
+            let lo: usize = lo.parse().unwrap_or(1);
+            let hi = if hi == "*" {
+                max
+            } else {
+                hi.parse().unwrap_or(max)
+            };
+            for n in lo..=hi.max(lo) {
+                if n >= 1 && n <= max {
+                    result.push(n);
+                }
+            }
+        } else if part == "*" {
+            if max >= 1 {
+                result.push(max);
+            }
+        } else if let Ok(n) = part.parse::<usize>() {
+            if n >= 1 && n <= max {
+                result.push(n);
+            }
+        }
+    }
+
+    result
+}
+
+/// Tokenize a `SEARCH` argument string, honouring RFC 3501 quoted strings.
+///
+/// A `"..."` run (with `\"` and `\\` escapes) is kept together as a single token with its quotes
+/// stripped, so `SUBJECT "hello world"` yields the two tokens `SUBJECT` and `hello world` rather
+/// than splitting on the embedded space. Outside of quotes, tokens are separated by whitespace,
+/// as for any other IMAP command argument.
+fn tokenize_search_criteria(rest: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = rest.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        if c == '"' {
+            chars.next();
+            let mut token = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            token.push(escaped);
+                        }
+                    }
+                    _ => token.push(c),
+                }
+            }
+            tokens.push(token);
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            tokens.push(token);
+        }
+    }
+
+    tokens
+}
+
+fn fetch(
+    writer: &mut impl Write,
+    tag: &str,
+    mailbox: &Option<Mailbox>,
+    rest: &str,
+    by_uid: bool,
+) -> io::Result<()> {
+    let mailbox = match mailbox {
+        Some(m) => m,
+        None => return write!(writer, "{} NO no mailbox selected\r\n", tag),
+    };
+
+    let mut parts = rest.splitn(2, ' ');
+    let seq_set = parts.next().unwrap_or("");
+    let items = parts.next().unwrap_or("").to_ascii_uppercase();
+
+    for seq in parse_seq_set(seq_set, mailbox.mails.len()) {
+        let mail = &mailbox.mails[seq - 1];
+        let uid = seq;
+        let mut data = Vec::new();
+
+        if by_uid || items.contains("UID") {
+            data.push(format!("UID {}", uid));
+        }
+        if items.contains("FLAGS") {
+            data.push("FLAGS (\\Seen)".to_owned());
+        }
+        if items.contains("INTERNALDATE") {
+            data.push(format!("INTERNALDATE \"{}\"", internal_date(mail)));
+        }
+        if items.contains("ENVELOPE") {
+            data.push(format!("ENVELOPE {}", envelope(mail)));
+        }
+        let send_body =
+            items.contains("RFC822") || items.contains("BODY[]") || items.contains("BODY.PEEK[]");
+
+        write!(writer, "* {} FETCH ({}", seq, data.join(" "))?;
+        if send_body {
+            // Write the raw bytes directly rather than a lossy UTF-8 re-encoding: for an
+            // invalid-UTF8 body those have different lengths, which would desync the `{N}`
+            // literal byte count from what is actually written.
+            if !data.is_empty() {
+                write!(writer, " ")?;
+            }
+            let raw = mail.body.as_bytes();
+            write!(writer, "BODY[] {{{}}}\r\n", raw.len())?;
+            writer.write_all(raw)?;
+        }
+        write!(writer, ")\r\n")?;
+    }
+
+    write!(writer, "{} OK FETCH completed\r\n", tag)
+}
+
+fn search(
+    writer: &mut impl Write,
+    tag: &str,
+    mailbox: &Option<Mailbox>,
+    rest: &str,
+    by_uid: bool,
+) -> io::Result<()> {
+    let mailbox = match mailbox {
+        Some(m) => m,
+        None => return write!(writer, "{} NO no mailbox selected\r\n", tag),
+    };
+
+    let criteria = tokenize_search_criteria(rest);
+    let mut matches = Vec::new();
+
+    'mails: for (i, mail) in mailbox.mails.iter().enumerate() {
+        let parsed = mail.parsed();
+        let mut j = 0;
+        while j < criteria.len() {
+            let key = criteria[j].to_ascii_uppercase();
+            let needs_value = matches!(key.as_str(), "FROM" | "TO" | "SUBJECT" | "SINCE");
+            let value = if needs_value {
+                j += 1;
+                criteria.get(j).map(String::as_str).unwrap_or("")
+            } else {
+                ""
+            };
+
+            let matched = match key.as_str() {
+                "ALL" => true,
+                "FROM" => parsed
+                    .header("From")
+                    .map(|h| h.to_ascii_lowercase().contains(&value.to_ascii_lowercase()))
+                    .unwrap_or(false),
+                "TO" => parsed
+                    .header("To")
+                    .map(|h| h.to_ascii_lowercase().contains(&value.to_ascii_lowercase()))
+                    .unwrap_or(false),
+                "SUBJECT" => parsed
+                    .header("Subject")
+                    .map(|h| h.to_ascii_lowercase().contains(&value.to_ascii_lowercase()))
+                    .unwrap_or(false),
+                // Matches mails whose internal date (the UTC day of `timestamp_us`) is on or
+                // after the given `dd-Mon-yyyy` date; an unparseable date matches nothing.
+                "SINCE" => match chrono::NaiveDate::parse_from_str(value, "%d-%b-%Y") {
+                    Ok(since) => chrono::NaiveDateTime::from_timestamp_opt(
+                        (mail.timestamp_us / 1_000_000) as i64,
+                        0,
+                    )
+                    .map(|dt| dt.date() >= since)
+                    .unwrap_or(false),
+                    Err(_) => false,
+                },
+                _ => true,
+            };
+
+            if !matched {
+                j += 1;
+                continue 'mails;
+            }
+            j += 1;
+        }
+        matches.push(if by_uid { i + 1 } else { i + 1 });
+    }
+
+    let ids: Vec<String> = matches.iter().map(|n| n.to_string()).collect();
+    write!(writer, "* SEARCH {}\r\n", ids.join(" "))?;
+    write!(writer, "{} OK SEARCH completed\r\n", tag)
+}
+
+/// Format a mail's timestamp as an IMAP `INTERNALDATE` string.
+fn internal_date(mail: &Mail) -> String {
+    let secs = (mail.timestamp_us / 1_000_000) as i64;
+    match chrono::NaiveDateTime::from_timestamp_opt(secs, 0) {
+        Some(dt) => dt.format("%d-%b-%Y %H:%M:%S +0000").to_string(),
+        None => "01-Jan-1970 00:00:00 +0000".to_owned(),
+    }
+}
+
+/// Build an IMAP `ENVELOPE` structure from a mail's headers.
+fn envelope(mail: &Mail) -> String {
+    let parsed = mail.parsed();
+    let date = parsed.header("Date").unwrap_or_default();
+    let subject = parsed.header("Subject").unwrap_or_default();
+    let from = address_list(parsed.header("From"));
+    let to = address_list(parsed.header("To"));
+    let cc = address_list(parsed.header("Cc"));
+    let bcc = address_list(parsed.header("Bcc"));
+    let in_reply_to = parsed.header("In-Reply-To").unwrap_or_default();
+    let message_id = parsed.header("Message-ID").unwrap_or_default();
+
+    format!(
+        "({} {} {} {} NIL {} {} {} {} {})",
+        quote(date),
+        quote(subject),
+        from,
+        from,
+        to,
+        cc,
+        bcc,
+        quote(in_reply_to),
+        quote(message_id),
+    )
+}
+
+/// Render an address-list header value as an IMAP address structure list, or `NIL` if absent.
+fn address_list(value: Option<&str>) -> String {
+    let value = match value {
+        Some(v) if !v.is_empty() => v,
+        _ => return "NIL".to_owned(),
+    };
+
+    let addresses: Vec<String> = parse_address_list(value)
+        .into_iter()
+        .map(|addr| {
+            let (mailbox, host) = addr.address.split_once('@').unwrap_or((&addr.address, ""));
+            format!(
+                "({} NIL {} {})",
+                match &addr.display_name {
+                    Some(name) => quote(name),
+                    None => "NIL".to_owned(),
+                },
+                quote(mailbox),
+                quote(host),
+            )
+        })
+        .collect();
+
+    if addresses.is_empty() {
+        "NIL".to_owned()
+    } else {
+        format!("({})", addresses.join(" "))
+    }
+}
+
+/// Render a string as an IMAP quoted string, or `NIL` if empty.
+fn quote(s: &str) -> String {
+    if s.is_empty() {
+        "NIL".to_owned()
+    } else {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CliOptions;
+
+    fn cli_options() -> CliOptions {
+        CliOptions {
+            debug: false,
+            ignore_dots: true,
+            inline_recipients: true,
+            options: Vec::new(),
+            dump: None,
+            option: Vec::new(),
+            sender: String::new(),
+            store_path: None,
+            serve_imap: None,
+        }
+    }
+
+    fn mail(raw_body: &str) -> Mail {
+        Mail::new(cli_options(), raw_body.as_bytes().to_vec(), Vec::new())
+    }
+
+    #[test]
+    fn tokenize_splits_unquoted_on_whitespace() {
+        assert_eq!(
+            tokenize_search_criteria("FROM foo@bar SINCE 1"),
+            vec!["FROM", "foo@bar", "SINCE", "1"],
+        );
+    }
+
+    #[test]
+    fn tokenize_keeps_quoted_argument_together() {
+        assert_eq!(
+            tokenize_search_criteria(r#"SUBJECT "hello world""#),
+            vec!["SUBJECT", "hello world"],
+        );
+    }
+
+    #[test]
+    fn tokenize_strips_quotes_from_single_word_value() {
+        assert_eq!(tokenize_search_criteria(r#"SUBJECT "hello""#), vec!["SUBJECT", "hello"]);
+    }
+
+    #[test]
+    fn tokenize_honours_backslash_escapes_inside_quotes() {
+        assert_eq!(
+            tokenize_search_criteria(r#"SUBJECT "a \"quoted\" word""#),
+            vec!["SUBJECT", "a \"quoted\" word"],
+        );
+    }
+
+    #[test]
+    fn parse_seq_set_expands_ranges_and_star() {
+        assert_eq!(parse_seq_set("1:3", 5), vec![1, 2, 3]);
+        assert_eq!(parse_seq_set("2:*", 4), vec![2, 3, 4]);
+        assert_eq!(parse_seq_set("1,3", 5), vec![1, 3]);
+        assert_eq!(parse_seq_set("1:10", 3), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn quote_escapes_backslashes_and_quotes_and_nils_empty() {
+        assert_eq!(quote(""), "NIL");
+        assert_eq!(quote("plain"), "\"plain\"");
+        assert_eq!(quote("a\"b\\c"), "\"a\\\"b\\\\c\"");
+    }
+
+    #[test]
+    fn search_matches_quoted_multi_word_subject() {
+        let mailbox = Some(Mailbox {
+            mails: vec![
+                mail("Subject: hello world\r\n\r\nbody"),
+                mail("Subject: unrelated\r\n\r\nbody"),
+            ],
+        });
+
+        let mut out = Vec::new();
+        search(&mut out, "A1", &mailbox, r#"SUBJECT "hello world""#, false).unwrap();
+        let response = String::from_utf8(out).unwrap();
+
+        assert!(response.starts_with("* SEARCH 1\r\n"));
+        assert!(response.contains("A1 OK SEARCH completed"));
+    }
+
+    #[test]
+    fn envelope_renders_addresses_and_nil_for_absent_headers() {
+        let m = mail("From: Santa <santa@example.com>\r\nSubject: hi\r\n\r\nbody");
+        let env = envelope(&m);
+        assert!(env.contains("\"hi\""));
+        assert!(env.contains("\"Santa\""));
+        assert!(env.contains("\"santa\""));
+        assert!(env.contains("\"example.com\""));
+        // No `In-Reply-To`/`Message-ID` header was set.
+        assert!(env.ends_with("NIL NIL)"));
+    }
+
+    #[test]
+    fn address_list_is_nil_when_header_absent() {
+        assert_eq!(address_list(None), "NIL");
+        assert_eq!(address_list(Some("")), "NIL");
+    }
+}
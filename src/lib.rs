@@ -23,9 +23,11 @@
 //!
 //! When `trapmail` receives a message, it stores it along with metadata a JSON file in the
 //! directory named in the `TRAPMAIL_STORE` environment variable, falling back to `/tmp` if
-//! not found. Files are named `trapmail_PPID_PID_TIMESTAMP.json`, where `PPID` is the parent
-//! process' PID, `PID` trapmails `PID` at the time of the call and `TIMESTAMP` a microsecond
-//! accurate timestamp.
+//! not found. Files are named `trapmail_TIMESTAMP_PPID_PID_N.json`, where `PPID` is the parent
+//! process' PID, `PID` trapmails `PID` at the time of the call, `TIMESTAMP` a microsecond
+//! accurate timestamp, and `N` a per-process counter disambiguating mails created within the
+//! same microsecond. Mails are written to a temporary file first and atomically renamed into
+//! place, so a concurrent reader never observes a partially-written file.
 //!
 //! ### Command-line options
 //!
@@ -94,17 +96,26 @@
 //!     println!("{}", mail);
 //! }
 //! ```
+pub mod imap_server;
+pub mod parsed_mail;
 pub mod serde_pid;
+mod sqlite_store;
 mod util;
+mod watch;
 
+use crate::parsed_mail::ParsedMail;
+use crate::sqlite_store::SqliteStore;
 use crate::util::FlattenResultsIter;
+pub use crate::util::{parse_address_list, Address};
+pub use crate::watch::Watch;
 use displaydoc::Display;
 use lazy_static::lazy_static;
 use nix::unistd::Pid;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use std::convert::TryInto;
-use std::{env, fmt, fs, io, path, thread, time};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{env, fmt, fs, io, path, time};
 use structopt::{clap, StructOpt};
 use thiserror::Error;
 
@@ -116,9 +127,15 @@ const DEFAULT_MAIL_STORE_PATH: &str = "/tmp";
 
 lazy_static! {
     /// Regular expression that matches filenames generated by `Mail`.
-    static ref FILENAME_RE: Regex = Regex::new(r"trapmail_\d+_\d+_\d+.json").unwrap();
+    ///
+    /// Anchored so that it does not match the `.tmp` staging files `MailStore::add` writes
+    /// before atomically renaming them into place.
+    static ref FILENAME_RE: Regex = Regex::new(r"^trapmail_\d+_\d+_\d+_\d+\.json$").unwrap();
 }
 
+/// Monotonic, per-process counter disambiguating mails created within the same microsecond.
+static MAIL_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 /// Command-line options for the `trapmail` program.
 #[derive(Clone, Debug, Deserialize, Serialize, StructOpt)]
 pub struct CliOptions {
@@ -145,6 +162,10 @@ pub struct CliOptions {
     /// The mail store path. Overrides the eponymous environment variable.
     #[structopt(long = "store-path")]
     pub store_path: Option<String>,
+    /// Ignore everything else and serve the mail store as a read-only IMAP server listening on
+    /// the given address (e.g. `127.0.0.1:1430`) instead.
+    #[structopt(long = "serve-imap")]
+    pub serve_imap: Option<String>,
 }
 
 impl CliOptions {
@@ -168,6 +189,8 @@ pub enum Error {
     Load(io::Error),
     /// "Could not deserialize mail: {0}
     MailDeserialization(serde_json::Error),
+    /// "SQLite mail store error: {0}
+    Sqlite(rusqlite::Error),
 }
 
 type Result<T> = ::std::result::Result<T, Error>;
@@ -208,6 +231,16 @@ impl fmt::Display for MailBody {
     }
 }
 
+impl MailBody {
+    /// Return the raw bytes of this body, regardless of whether it is valid UTF-8.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            MailBody::Utf8(s) => s.as_bytes(),
+            MailBody::Invalid(raw) => raw,
+        }
+    }
+}
+
 /// A "sent" mail.
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Mail {
@@ -223,41 +256,45 @@ pub struct Mail {
     pub body: MailBody,
     /// A microsecond-resolution UNIX timestamp of when the mail arrived.
     pub timestamp_us: u128,
+    /// The resolved envelope recipient set: command-line addresses plus, when `-t` was given,
+    /// every address found in the `To`, `Cc` and `Bcc` headers.
+    pub recipients: Vec<String>,
+    /// A per-process counter value disambiguating this mail from others created within the
+    /// same microsecond (see `file_name`).
+    pub disambiguator: u64,
 }
 
 impl Mail {
     /// Create a new `Mail` using the current time and process information.
     ///
-    /// This function will sleep for a microsecond to avoid any conflicts in
-    /// naming (see `file_name`).
-    ///
     /// # Panics
     ///
     /// Will panic if the system returns a time before the UNIX epoch.
-    pub fn new(cli_options: CliOptions, raw_body: Vec<u8>) -> Self {
-        // We always sleep a microsecond, which is probably overkill, but
-        // guarantees no collisions, ever (a millions mails a second ought
-        // to be enough for even future test cases).
-        thread::sleep(time::Duration::from_nanos(1000));
-
+    pub fn new(cli_options: CliOptions, raw_body: Vec<u8>, recipients: Vec<String>) -> Self {
         let timestamp_us = (time::SystemTime::now().duration_since(time::UNIX_EPOCH))
             .expect("Got current before 1970; is your clock broken?")
             .as_micros();
 
+        // Guarantees a unique `file_name` even for multiple mails created within the same
+        // microsecond in this process, without having to sleep to force the clock forward.
+        let disambiguator = MAIL_COUNTER.fetch_add(1, Ordering::Relaxed);
+
         Mail {
             cli_options,
             body: MailBody::from_raw(raw_body),
             pid: nix::unistd::Pid::this(),
             ppid: nix::unistd::Pid::parent(),
             timestamp_us,
+            recipients,
+            disambiguator,
         }
     }
 
     /// Create a (pathless) file_name depending on the `Mail` contents.
     pub fn file_name(&self) -> path::PathBuf {
         format!(
-            "trapmail_{}_{}_{}.json",
-            self.timestamp_us, self.ppid, self.pid,
+            "trapmail_{}_{}_{}_{}.json",
+            self.timestamp_us, self.ppid, self.pid, self.disambiguator,
         )
         .into()
     }
@@ -267,6 +304,20 @@ impl Mail {
         serde_json::from_reader(fs::File::open(source).map_err(Error::Load)?)
             .map_err(Error::MailDeserialization)
     }
+
+    /// Parse this mail's body into its headers and MIME parts.
+    ///
+    /// The body is re-parsed on every call; nothing is cached on `Mail` itself.
+    pub fn parsed(&self) -> ParsedMail {
+        ParsedMail::parse(self.body.as_bytes())
+    }
+
+    /// Return the value of the first header field matching `name`, case-insensitively.
+    ///
+    /// Shorthand for `self.parsed().header(name)` that also owns its result.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.parsed().header(name).map(str::to_owned)
+    }
 }
 
 /// Convert microsecond timestamp to `chrono::NaiveDateTime`.
@@ -299,49 +350,135 @@ impl fmt::Display for Mail {
     }
 }
 
+/// A storage backend used by a `MailStore`.
+#[derive(Debug)]
+enum Backend {
+    /// Each mail is a `trapmail_*.json` file below this root directory.
+    Filesystem(path::PathBuf),
+    /// Mails are indexed rows in a SQLite database.
+    Sqlite(SqliteStore),
+}
+
 /// Mail storage.
+///
+/// Backed by the filesystem by default; see `MailStore::with_sqlite` for the opt-in
+/// SQLite-backed alternative, which is better suited to test suites emitting many mails.
 #[derive(Debug)]
 pub struct MailStore {
-    /// Root path where all mail in this store gets stored.
-    root: path::PathBuf,
+    backend: Backend,
 }
 
 impl MailStore {
     /// Construct a new `MailStore`.
     ///
-    /// The path will be set from the environment or use a default, if not set.
+    /// The path will be set from the environment or use a default, if not set. If the
+    /// environment variable points at a path ending in `.db`, a SQLite-backed store is opened
+    /// instead of the filesystem store.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the path ends in `.db` and the SQLite database at that path could not be
+    /// opened. Use `MailStore::with_sqlite` directly if you need to handle that case instead of
+    /// aborting.
     #[inline]
     pub fn new() -> Self {
-        MailStore::with_root(
-            env::var(ENV_MAIL_STORE_PATH).unwrap_or_else(|_| DEFAULT_MAIL_STORE_PATH.to_owned()),
-        )
+        let root =
+            env::var(ENV_MAIL_STORE_PATH).unwrap_or_else(|_| DEFAULT_MAIL_STORE_PATH.to_owned());
+
+        if root.ends_with(".db") {
+            Self::with_sqlite(root).expect("could not open sqlite mail store")
+        } else {
+            Self::with_root(root)
+        }
     }
 
-    /// Construct a new `MailStore` with given path.
+    /// Construct a new filesystem-backed `MailStore` with given root directory.
     #[inline]
     pub fn with_root<P: Into<path::PathBuf>>(root: P) -> Self {
-        MailStore { root: root.into() }
+        MailStore {
+            backend: Backend::Filesystem(root.into()),
+        }
+    }
+
+    /// Construct a new SQLite-backed `MailStore`, creating the database file if it does not
+    /// already exist.
+    #[inline]
+    pub fn with_sqlite<P: AsRef<path::Path>>(db_path: P) -> Result<Self> {
+        Ok(MailStore {
+            backend: Backend::Sqlite(SqliteStore::open(db_path)?),
+        })
     }
 
     /// Add a mail to the `MailStore`.
     ///
-    /// Returns the path where the mail has been stored.
-    pub fn add(&self, mail: &Mail) -> Result<path::PathBuf> {
-        let output_fn = self.root.join(mail.file_name());
+    /// Returns the path the mail was written to, for the filesystem backend; `None` for the
+    /// SQLite backend, which has no equivalent notion of a file path.
+    pub fn add(&self, mail: &Mail) -> Result<Option<path::PathBuf>> {
+        match &self.backend {
+            Backend::Filesystem(root) => {
+                let output_fn = root.join(mail.file_name());
+                let tmp_fn = root.join(format!("{}.tmp", mail.file_name().display()));
 
-        serde_json::to_writer_pretty(fs::File::create(&output_fn).map_err(Error::Store)?, mail)
-            .map_err(Error::MailSerialization)?;
-        Ok(output_fn)
+                // Write to a temporary file and `rename` it into place, so a concurrent
+                // `iter_mails` can never observe a partially-written file.
+                let mut tmp_file = fs::File::create(&tmp_fn).map_err(Error::Store)?;
+                serde_json::to_writer_pretty(&mut tmp_file, mail)
+                    .map_err(Error::MailSerialization)?;
+                tmp_file.sync_all().map_err(Error::Store)?;
+                drop(tmp_file);
+                fs::rename(&tmp_fn, &output_fn).map_err(Error::Store)?;
+
+                Ok(Some(output_fn))
+            }
+            Backend::Sqlite(store) => {
+                store.add(mail)?;
+                Ok(None)
+            }
+        }
     }
 
     /// Iterate over all mails in storage.
     ///
     /// Mails are ordered by timestamp.
-    pub fn iter_mails(&self) -> impl Iterator<Item = Result<Mail>> {
-        util::read_dir_matching(&self.root, &FILENAME_RE)
-            .map_err(Error::DirEnumeration)
-            .map(|paths| paths.into_iter().map(Mail::load))
-            .flatten_results()
+    pub fn iter_mails(&self) -> Box<dyn Iterator<Item = Result<Mail>>> {
+        match &self.backend {
+            Backend::Filesystem(root) => Box::new(
+                util::read_dir_matching(root, &FILENAME_RE)
+                    .map_err(Error::DirEnumeration)
+                    .map(|paths| paths.into_iter().map(Mail::load))
+                    .flatten_results(),
+            ),
+            Backend::Sqlite(store) => match store.iter_mails() {
+                Ok(mails) => Box::new(mails.into_iter().map(Ok)),
+                Err(e) => Box::new(std::iter::once(Err(e))),
+            },
+        }
+    }
+
+    /// Return the filesystem root this store writes into, if it is filesystem-backed.
+    ///
+    /// Used by `watch` to know what directory to hand to a filesystem notifier; the SQLite
+    /// backend has no such path and always falls back to polling.
+    pub(crate) fn fs_root(&self) -> Option<&path::Path> {
+        match &self.backend {
+            Backend::Filesystem(root) => Some(root.as_path()),
+            Backend::Sqlite(_) => None,
+        }
+    }
+
+    /// Start building a query against this store.
+    ///
+    /// On the SQLite backend, this runs as an indexed `SELECT`; on the filesystem backend, it
+    /// falls back to filtering the result of `iter_mails`.
+    #[inline]
+    pub fn query(&self) -> Query<'_> {
+        Query {
+            store: self,
+            sender: None,
+            recipient: None,
+            subject: None,
+            since: None,
+        }
     }
 }
 
@@ -350,3 +487,177 @@ impl Default for MailStore {
         Self::new()
     }
 }
+
+/// A builder for querying a `MailStore` by sender, recipient, subject and/or minimum timestamp.
+///
+/// Constructed via `MailStore::query`.
+#[derive(Debug)]
+pub struct Query<'a> {
+    store: &'a MailStore,
+    sender: Option<String>,
+    recipient: Option<String>,
+    subject: Option<String>,
+    since: Option<u128>,
+}
+
+impl<'a> Query<'a> {
+    /// Only match mails whose envelope sender (the address parsed out of the `From` header,
+    /// ignoring any display name) is exactly `sender`.
+    pub fn sender(mut self, sender: impl Into<String>) -> Self {
+        self.sender = Some(sender.into());
+        self
+    }
+
+    /// Only match mails with a resolved recipient (see `Mail::recipients`) containing
+    /// `recipient` as a substring.
+    pub fn recipient(mut self, recipient: impl Into<String>) -> Self {
+        self.recipient = Some(recipient.into());
+        self
+    }
+
+    /// Only match mails whose `Subject` header is exactly `subject`.
+    pub fn subject(mut self, subject: impl Into<String>) -> Self {
+        self.subject = Some(subject.into());
+        self
+    }
+
+    /// Only match mails with `timestamp_us >= since`.
+    pub fn since(mut self, since: u128) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Run the query, returning matching mails ordered by timestamp.
+    pub fn exec(self) -> Result<Vec<Mail>> {
+        match &self.store.backend {
+            Backend::Sqlite(store) => store.query(
+                self.sender.as_deref(),
+                self.recipient.as_deref(),
+                self.subject.as_deref(),
+                self.since,
+            ),
+            Backend::Filesystem(_) => {
+                let mut mails = Vec::new();
+                for mail in self.store.iter_mails() {
+                    let mail = mail?;
+                    if self.matches(&mail) {
+                        mails.push(mail);
+                    }
+                }
+                mails.sort_by_key(|m| m.timestamp_us);
+                Ok(mails)
+            }
+        }
+    }
+
+    /// Check whether `mail` satisfies all filters set on this query.
+    fn matches(&self, mail: &Mail) -> bool {
+        let parsed = mail.parsed();
+
+        if let Some(sender) = &self.sender {
+            // Compare the parsed address, not the raw header: `From` commonly carries a
+            // display name (`Marc <marc@example.com>`), which would never equal a bare address.
+            let from_address = parsed
+                .header("From")
+                .and_then(|from| parse_address_list(from).into_iter().next())
+                .map(|addr| addr.address);
+            if from_address.as_deref() != Some(sender.as_str()) {
+                return false;
+            }
+        }
+        if let Some(recipient) = &self.recipient {
+            // Match against the resolved envelope recipient set, not the raw `To` header: that
+            // header is what `-t` merges `Cc`/`Bcc` and command-line addresses into, and `Bcc`
+            // is stripped from the persisted body, so re-deriving from headers would make
+            // Bcc'd mail permanently unqueryable.
+            if !mail
+                .recipients
+                .iter()
+                .any(|r| r.contains(recipient.as_str()))
+            {
+                return false;
+            }
+        }
+        if let Some(subject) = &self.subject {
+            if parsed.header("Subject") != Some(subject.as_str()) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if mail.timestamp_us < since {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli_options() -> CliOptions {
+        CliOptions {
+            debug: false,
+            ignore_dots: true,
+            inline_recipients: true,
+            options: Vec::new(),
+            dump: None,
+            option: Vec::new(),
+            sender: String::new(),
+            store_path: None,
+            serve_imap: None,
+        }
+    }
+
+    fn test_dir(name: &str) -> path::PathBuf {
+        let dir = env::temp_dir().join(format!("trapmail-lib-test-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn mails_created_back_to_back_get_distinct_disambiguators_and_file_names() {
+        let a = Mail::new(cli_options(), b"body".to_vec(), Vec::new());
+        let b = Mail::new(cli_options(), b"body".to_vec(), Vec::new());
+
+        assert_ne!(a.disambiguator, b.disambiguator);
+        assert_ne!(a.file_name(), b.file_name());
+    }
+
+    #[test]
+    fn add_writes_file_named_after_mail_with_no_leftover_tmp_file() {
+        let dir = test_dir("atomic-write");
+        let store = MailStore::with_root(dir.clone());
+        let mail = Mail::new(cli_options(), b"Subject: s\r\n\r\nbody".to_vec(), Vec::new());
+
+        let path = store.add(&mail).unwrap().expect("filesystem backend returns a path");
+        assert_eq!(path, dir.join(mail.file_name()));
+        assert!(path.exists());
+
+        let tmp_path = dir.join(format!("{}.tmp", mail.file_name().display()));
+        assert!(!tmp_path.exists());
+
+        let loaded = Mail::load(&path).unwrap();
+        assert_eq!(loaded.disambiguator, mail.disambiguator);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn iter_mails_sees_every_mail_written_by_add_even_within_the_same_microsecond() {
+        let dir = test_dir("iter-mails");
+        let store = MailStore::with_root(dir.clone());
+
+        for _ in 0..5 {
+            let mail = Mail::new(cli_options(), b"Subject: s\r\n\r\nbody".to_vec(), Vec::new());
+            store.add(&mail).unwrap();
+        }
+
+        let mails: Vec<Mail> = store.iter_mails().collect::<Result<_>>().unwrap();
+        assert_eq!(mails.len(), 5);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -1,7 +1,9 @@
 use failure::bail;
+use std::collections::HashSet;
 use std::io;
 use std::io::Read;
 use structopt::StructOpt;
+use trapmail::parsed_mail::ParsedMail;
 use trapmail::MailStore;
 
 fn main() -> Result<(), failure::Error> {
@@ -14,6 +16,17 @@ fn main() -> Result<(), failure::Error> {
         return Ok(());
     }
 
+    // Likewise, `--serve-imap` ignores everything else and runs forever.
+    if let Some(addr) = opt.serve_imap {
+        let store = opt
+            .store_path
+            .as_ref()
+            .map(MailStore::with_root)
+            .unwrap_or_else(MailStore::new);
+        trapmail::imap_server::serve(store, addr)?;
+        return Ok(());
+    }
+
     if !opt.ignore_dots {
         bail!("ignore dots (`-i`) was not set, but the reverse is not supported");
     }
@@ -33,11 +46,34 @@ fn main() -> Result<(), failure::Error> {
     let mut buffer = Vec::new();
     io::stdin().read_to_end(&mut buffer)?;
 
-    let mail = trapmail::Mail::new(opt.clone(), buffer);
+    // `-t` means we derive the recipient set from the message's own `To`/`Cc`/`Bcc` headers,
+    // merging it with any addresses given on the command line, and hide `Bcc` from what
+    // actually gets persisted.
+    let mut recipients = opt.options.clone();
+    if opt.inline_recipients {
+        let parsed = ParsedMail::parse(&buffer);
+        for header in &["To", "Cc", "Bcc"] {
+            if let Some(value) = parsed.header(header) {
+                recipients.extend(
+                    trapmail::parse_address_list(value)
+                        .into_iter()
+                        .map(|addr| addr.address),
+                );
+            }
+        }
+        buffer = trapmail::parsed_mail::strip_header(&buffer, "Bcc");
+    }
+    let mut seen = HashSet::new();
+    recipients.retain(|r| seen.insert(r.clone()));
+
+    let mail = trapmail::Mail::new(opt.clone(), buffer, recipients);
     let storage_path = store.add(&mail)?;
 
     if opt.debug {
-        eprintln!("Mail written to {:?}", storage_path);
+        match storage_path {
+            Some(path) => eprintln!("Mail written to {:?}", path),
+            None => eprintln!("Mail written to store"),
+        }
     }
 
     Ok(())
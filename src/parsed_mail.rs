@@ -0,0 +1,429 @@
+//! Structured parsing of raw mail bodies into headers and MIME parts.
+//!
+//! `MailBody` only distinguishes valid UTF-8 from invalid raw bytes; this module adds an
+//! on-demand, best-effort decomposition of that raw body into RFC 5322 headers and, for
+//! `multipart/...` messages, a tree of sub-parts. It does not change what gets stored on disk,
+//! `Mail::parsed` simply re-parses `Mail::body` each time it is called.
+
+use std::fmt;
+
+/// An ordered multimap of header field names to their (unfolded) values.
+///
+/// Header order and duplicate fields (e.g. multiple `Received` lines) are preserved, matching
+/// how the fields appeared in the original message.
+#[derive(Debug, Clone, Default)]
+pub struct HeaderMap(Vec<(String, String)>);
+
+impl HeaderMap {
+    /// Return the value of the first header field matching `name`, case-insensitively.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return all values of header fields matching `name`, case-insensitively, in order.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Iterate over all header fields in their original order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Parse a block of raw header bytes (without the trailing blank line separator), unfolding
+    /// continuation lines that begin with a space or tab.
+    ///
+    /// Headers are lossy-decoded to UTF-8 here, since their structure (field names, folding)
+    /// must be read as text; this is fine because headers are expected to be ASCII, and this
+    /// module's byte-fidelity guarantee only applies to leaf bodies, not header text.
+    fn parse(raw: &[u8]) -> Self {
+        let raw = String::from_utf8_lossy(raw);
+        let mut fields: Vec<(String, String)> = Vec::new();
+
+        for line in raw.split("\r\n").flat_map(|l| l.split('\n')) {
+            if line.is_empty() {
+                continue;
+            }
+
+            if (line.starts_with(' ') || line.starts_with('\t')) && !fields.is_empty() {
+                let last = fields.last_mut().expect("just checked non-empty");
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+                continue;
+            }
+
+            if let Some(colon) = line.find(':') {
+                let name = line[..colon].trim().to_owned();
+                let value = line[colon + 1..].trim().to_owned();
+                fields.push((name, value));
+            }
+            // Lines that are neither a folded continuation nor `Name: value` are ignored.
+        }
+
+        HeaderMap(fields)
+    }
+}
+
+/// The content of a parsed MIME entity.
+#[derive(Debug, Clone)]
+pub enum MailContent {
+    /// A single, non-multipart body, decoded according to `Content-Transfer-Encoding`.
+    Leaf(Vec<u8>),
+    /// A `multipart/...` body, broken down into its sub-parts in order.
+    Multipart(Vec<ParsedMail>),
+}
+
+/// A mail (or MIME sub-part), split into its headers and content.
+#[derive(Debug, Clone)]
+pub struct ParsedMail {
+    /// The headers of this part.
+    pub headers: HeaderMap,
+    /// The content of this part; either a leaf body or nested sub-parts.
+    pub content: MailContent,
+}
+
+impl ParsedMail {
+    /// Parse a raw, RFC 5322-ish message (or MIME sub-part) into headers and content.
+    ///
+    /// This is deliberately lenient: inputs that do not conform to RFC 5322 still yield a best
+    /// effort result rather than an error, since `trapmail` must be able to display whatever a
+    /// test sent it.
+    ///
+    /// Splitting and leaf decoding operate on the raw bytes directly; only header text is
+    /// lossy-decoded to UTF-8. A leaf body with no `Content-Transfer-Encoding` is therefore
+    /// returned byte-for-byte, even if it is not valid UTF-8.
+    pub fn parse(raw: &[u8]) -> Self {
+        let (header_block, body) = split_headers(raw);
+        let headers = HeaderMap::parse(header_block);
+
+        let content = if let Some(boundary) = multipart_boundary(&headers) {
+            MailContent::Multipart(split_multipart(body, boundary.as_bytes()))
+        } else {
+            MailContent::Leaf(decode_body(body, &headers))
+        };
+
+        ParsedMail { headers, content }
+    }
+
+    /// Return the value of the first header field matching `name`, case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// Iterate over the immediate sub-parts of a multipart message.
+    ///
+    /// Returns an empty iterator for a non-multipart (`Leaf`) message.
+    pub fn parts(&self) -> impl Iterator<Item = &ParsedMail> {
+        match &self.content {
+            MailContent::Multipart(parts) => parts.iter(),
+            MailContent::Leaf(_) => [].iter(),
+        }
+    }
+}
+
+impl fmt::Display for ParsedMail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in self.headers.iter() {
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        writeln!(f)?;
+        match &self.content {
+            MailContent::Leaf(body) => write!(f, "{}", String::from_utf8_lossy(body)),
+            MailContent::Multipart(parts) => {
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", part)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Remove all header fields named `name` (case-insensitively), including any folded
+/// continuation lines, from a raw message.
+///
+/// Used to strip `Bcc` before persisting a delivered message, mirroring how real MTAs hide
+/// blind-carbon-copy recipients from the stored copy. The body is copied through byte-for-byte;
+/// only the header block is read as text.
+pub fn strip_header(raw: &[u8], name: &str) -> Vec<u8> {
+    let (header_block, body) = split_headers(raw);
+    let header_block = String::from_utf8_lossy(header_block);
+    let newline = if header_block.contains("\r\n") { "\r\n" } else { "\n" };
+
+    let mut kept_lines: Vec<&str> = Vec::new();
+    let mut skipping = false;
+
+    for line in header_block.split(newline) {
+        let is_continuation = line.starts_with(' ') || line.starts_with('\t');
+        if is_continuation {
+            if !skipping {
+                kept_lines.push(line);
+            }
+            continue;
+        }
+
+        skipping = line
+            .split_once(':')
+            .map(|(field, _)| field.trim().eq_ignore_ascii_case(name))
+            .unwrap_or(false);
+
+        if !skipping {
+            kept_lines.push(line);
+        }
+    }
+
+    let mut out = kept_lines.join(newline).into_bytes();
+    out.extend_from_slice(newline.as_bytes());
+    out.extend_from_slice(newline.as_bytes());
+    out.extend_from_slice(body);
+    out
+}
+
+/// Find the first occurrence of `needle` in `haystack`, if any.
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return None;
+    }
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Split `haystack` on every (non-overlapping) occurrence of `needle`, like `str::split` but for
+/// byte slices.
+fn split_bytes<'a>(haystack: &'a [u8], needle: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+
+    while let Some(pos) = find_subslice(&haystack[start..], needle) {
+        parts.push(&haystack[start..start + pos]);
+        start += pos + needle.len();
+    }
+    parts.push(&haystack[start..]);
+
+    parts
+}
+
+/// Split `raw` at the first blank line into a header block and the remaining (raw) body.
+fn split_headers(raw: &[u8]) -> (&[u8], &[u8]) {
+    if let Some(idx) = find_subslice(raw, b"\r\n\r\n") {
+        (&raw[..idx], &raw[idx + 4..])
+    } else if let Some(idx) = find_subslice(raw, b"\n\n") {
+        (&raw[..idx], &raw[idx + 2..])
+    } else {
+        (raw, &[])
+    }
+}
+
+/// Extract the `boundary` parameter from a `Content-Type: multipart/...` header, if present.
+fn multipart_boundary(headers: &HeaderMap) -> Option<String> {
+    let content_type = headers.get("Content-Type")?;
+    if !content_type.trim_start().to_ascii_lowercase().starts_with("multipart/") {
+        return None;
+    }
+
+    content_type.split(';').skip(1).find_map(|param| {
+        let param = param.trim();
+        let value = param.strip_prefix("boundary=")?;
+        Some(value.trim_matches('"').to_owned())
+    })
+}
+
+/// Split a multipart body on `--boundary` delimiters (ending at `--boundary--`) and parse each
+/// resulting chunk as its own `ParsedMail`.
+fn split_multipart(body: &[u8], boundary: &[u8]) -> Vec<ParsedMail> {
+    let mut delimiter = Vec::with_capacity(boundary.len() + 2);
+    delimiter.extend_from_slice(b"--");
+    delimiter.extend_from_slice(boundary);
+
+    let mut parts = Vec::new();
+
+    for chunk in split_bytes(body, &delimiter).into_iter().skip(1) {
+        let chunk = chunk
+            .strip_prefix(b"\r\n".as_slice())
+            .or_else(|| chunk.strip_prefix(b"\n".as_slice()))
+            .unwrap_or(chunk);
+        if chunk.starts_with(b"--") {
+            // Final delimiter (`--boundary--`); nothing follows it.
+            break;
+        }
+        parts.push(ParsedMail::parse(chunk));
+    }
+
+    parts
+}
+
+/// Decode a leaf body according to its `Content-Transfer-Encoding` header, defaulting to
+/// passing the bytes through unmodified.
+///
+/// Only the `base64`/`quoted-printable` branches read `body` as text: both encodings are
+/// themselves restricted to 7-bit ASCII, so that lossy decode cannot lose information. The
+/// default (no transfer encoding, or an unrecognized one) copies `body` through byte-for-byte,
+/// so arbitrary binary or 8-bit leaf bodies round-trip exactly.
+fn decode_body(body: &[u8], headers: &HeaderMap) -> Vec<u8> {
+    match headers
+        .get("Content-Transfer-Encoding")
+        .map(|e| e.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("base64") => decode_base64(&String::from_utf8_lossy(body)),
+        Some("quoted-printable") => decode_quoted_printable(&String::from_utf8_lossy(body)),
+        _ => body.to_vec(),
+    }
+}
+
+/// Minimal, lenient base64 decoder: unknown characters (including line breaks) are skipped
+/// rather than rejected, since captured mail is not guaranteed to be well-formed.
+fn decode_base64(input: &str) -> Vec<u8> {
+    fn value(c: u8) -> Option<u8> {
+        match c {
+            b'A'..=b'Z' => Some(c - b'A'),
+            b'a'..=b'z' => Some(c - b'a' + 26),
+            b'0'..=b'9' => Some(c - b'0' + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let digits: Vec<u8> = input.bytes().filter_map(value).collect();
+    let mut out = Vec::with_capacity(digits.len() * 3 / 4);
+
+    for chunk in digits.chunks(4) {
+        let mut buf = [0u8; 4];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        out.push((buf[0] << 2) | (buf[1] >> 4));
+        if chunk.len() > 2 {
+            out.push((buf[1] << 4) | (buf[2] >> 2));
+        }
+        if chunk.len() > 3 {
+            out.push((buf[2] << 6) | buf[3]);
+        }
+    }
+
+    out
+}
+
+/// Minimal quoted-printable decoder, handling `=XX` escapes and soft line breaks (`=` at
+/// end-of-line).
+fn decode_quoted_printable(input: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    let bytes = input.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'=' if bytes[i..].starts_with(b"=\r\n") => i += 3,
+            b'=' if bytes[i..].starts_with(b"=\n") => i += 2,
+            b'=' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                if let Some(byte) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    out.push(byte);
+                    i += 3;
+                } else {
+                    out.push(bytes[i]);
+                    i += 1;
+                }
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unfolds_continuation_lines() {
+        let headers = HeaderMap::parse(b"Subject: hello\r\n world\r\nFrom: a@b.com");
+        assert_eq!(headers.get("Subject"), Some("hello world"));
+        assert_eq!(headers.get("From"), Some("a@b.com"));
+    }
+
+    #[test]
+    fn get_is_case_insensitive_and_get_all_preserves_order() {
+        let headers = HeaderMap::parse(b"Received: one\r\nreceived: two");
+        assert_eq!(headers.get("RECEIVED"), Some("one"));
+        assert_eq!(headers.get_all("Received").collect::<Vec<_>>(), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn leaf_body_with_no_transfer_encoding_round_trips_non_utf8_bytes() {
+        let mut raw = b"Content-Type: application/octet-stream\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0xff, 0x00, 0xfe, b'x']);
+
+        let parsed = ParsedMail::parse(&raw);
+        match parsed.content {
+            MailContent::Leaf(body) => assert_eq!(body, vec![0xff, 0x00, 0xfe, b'x']),
+            MailContent::Multipart(_) => panic!("expected a leaf body"),
+        }
+    }
+
+    #[test]
+    fn decodes_base64_leaf_body() {
+        let raw = b"Content-Transfer-Encoding: base64\r\n\r\naGVsbG8=";
+        let parsed = ParsedMail::parse(raw);
+        match parsed.content {
+            MailContent::Leaf(body) => assert_eq!(body, b"hello"),
+            MailContent::Multipart(_) => panic!("expected a leaf body"),
+        }
+    }
+
+    #[test]
+    fn decodes_quoted_printable_leaf_body() {
+        let raw = b"Content-Transfer-Encoding: quoted-printable\r\n\r\nhello=3Dworld";
+        let parsed = ParsedMail::parse(raw);
+        match parsed.content {
+            MailContent::Leaf(body) => assert_eq!(body, b"hello=world"),
+            MailContent::Multipart(_) => panic!("expected a leaf body"),
+        }
+    }
+
+    #[test]
+    fn splits_multipart_into_sub_parts() {
+        let raw = b"Content-Type: multipart/mixed; boundary=\"X\"\r\n\
+                    \r\n\
+                    --X\r\n\
+                    Subject: first\r\n\
+                    \r\n\
+                    one\r\n\
+                    --X\r\n\
+                    Subject: second\r\n\
+                    \r\n\
+                    two\r\n\
+                    --X--\r\n";
+
+        let parsed = ParsedMail::parse(raw);
+        let parts: Vec<&ParsedMail> = parsed.parts().collect();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0].header("Subject"), Some("first"));
+        assert_eq!(parts[1].header("Subject"), Some("second"));
+    }
+
+    #[test]
+    fn strip_header_removes_field_and_folded_continuation_but_keeps_body_bytes() {
+        let mut raw = b"To: a@b.com\r\nBcc: hidden@example.com,\r\n more@example.com\r\nSubject: s\r\n\r\n".to_vec();
+        raw.extend_from_slice(&[0xff, b'!']);
+
+        let out = strip_header(&raw, "Bcc");
+        let (header_block, body) = split_headers(&out);
+        let header_text = String::from_utf8_lossy(header_block);
+
+        assert!(!header_text.to_ascii_lowercase().contains("bcc"));
+        assert!(header_text.contains("To: a@b.com"));
+        assert_eq!(body, &[0xff, b'!']);
+    }
+}
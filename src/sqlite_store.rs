@@ -0,0 +1,269 @@
+//! A SQLite-backed storage backend for `MailStore`.
+//!
+//! Unlike the default filesystem backend, which has to deserialize every `trapmail_*.json` file
+//! to answer any query, this backend indexes a handful of commonly-queried fields (sender,
+//! recipients, subject, timestamp) in a `rusqlite`-backed table, alongside the full JSON blob
+//! needed to reconstruct the original `Mail` exactly.
+
+use crate::{parse_address_list, Error, Mail, Result};
+use rusqlite::{params, Connection};
+use std::fmt;
+use std::path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS mails (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    pid INTEGER NOT NULL,
+    ppid INTEGER NOT NULL,
+    timestamp_us INTEGER NOT NULL,
+    sender TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    blob TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS mails_sender_idx ON mails (sender);
+CREATE INDEX IF NOT EXISTS mails_recipients_idx ON mails (recipients);
+CREATE INDEX IF NOT EXISTS mails_subject_idx ON mails (subject);
+CREATE INDEX IF NOT EXISTS mails_timestamp_idx ON mails (timestamp_us);
+";
+
+/// A SQLite-backed mail store.
+///
+/// The connection is wrapped in a `Mutex` since `rusqlite::Connection` is not `Sync`, but
+/// `MailStore` is expected to be shared between threads (e.g. a test and the application under
+/// test writing to the same store).
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (creating if necessary) a SQLite-backed mail store at `path`.
+    ///
+    /// Enables WAL mode and a busy timeout: `trapmail` is meant to be written to concurrently
+    /// (e.g. several processes under test each calling `trapmail` at once), and SQLite's default
+    /// journal mode returns `SQLITE_BUSY` immediately rather than waiting for a writer to finish.
+    pub fn open<P: AsRef<path::Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path).map_err(Error::Sqlite)?;
+        conn.busy_timeout(Duration::from_secs(5))
+            .map_err(Error::Sqlite)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(Error::Sqlite)?;
+        // `LIKE` is ASCII case-insensitive by default; make recipient queries case-sensitive
+        // like the filesystem backend's `str::contains`.
+        conn.pragma_update(None, "case_sensitive_like", "ON")
+            .map_err(Error::Sqlite)?;
+        conn.execute_batch(SCHEMA).map_err(Error::Sqlite)?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Insert `mail`, indexing its sender, recipients, subject and timestamp.
+    pub fn add(&self, mail: &Mail) -> Result<()> {
+        let parsed = mail.parsed();
+        // Index the parsed address, not the raw `From` header: the header commonly carries a
+        // display name (`Marc <marc@example.com>`), which an exact-match query would never hit.
+        let sender = parsed
+            .header("From")
+            .and_then(|from| parse_address_list(from).into_iter().next())
+            .map(|addr| addr.address)
+            .unwrap_or_default();
+        // Index the resolved envelope recipient set, not the raw `To` header: `-t` merges in
+        // `Cc`/`Bcc` and command-line addresses, and `Bcc` is stripped from the persisted body,
+        // so re-parsing headers here would make Bcc'd mail permanently unqueryable.
+        let recipients = mail.recipients.join(",");
+        let subject = parsed.header("Subject").unwrap_or_default();
+        let blob = serde_json::to_string(mail).map_err(Error::MailSerialization)?;
+
+        self.conn()
+            .execute(
+                "INSERT INTO mails (pid, ppid, timestamp_us, sender, recipients, subject, blob) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    mail.pid.as_raw(),
+                    mail.ppid.as_raw(),
+                    mail.timestamp_us as i64,
+                    sender,
+                    recipients,
+                    subject,
+                    blob,
+                ],
+            )
+            .map_err(Error::Sqlite)?;
+        Ok(())
+    }
+
+    /// Return all stored mail, ordered by timestamp, without filtering on any indexed column.
+    pub fn iter_mails(&self) -> Result<Vec<Mail>> {
+        self.query(None, None, None, None)
+    }
+
+    /// Run an indexed query against the store, filtering by any combination of sender,
+    /// recipient substring, exact subject and minimum timestamp.
+    ///
+    /// `recipient` is matched against the comma-joined resolved recipient set (see
+    /// `Mail::recipients`) via `LIKE`, since a mail may have more than one recipient.
+    pub fn query(
+        &self,
+        sender: Option<&str>,
+        recipient: Option<&str>,
+        subject: Option<&str>,
+        since: Option<u128>,
+    ) -> Result<Vec<Mail>> {
+        let mut sql = String::from("SELECT blob FROM mails WHERE 1 = 1");
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = Vec::new();
+
+        if let Some(sender) = sender {
+            sql.push_str(" AND sender = ?");
+            values.push(Box::new(sender.to_owned()));
+        }
+        if let Some(recipient) = recipient {
+            // Escape the `LIKE` wildcards `%`/`_` (and the escape character itself) so that a
+            // recipient substring containing them is matched literally, matching the plain
+            // `str::contains` semantics the filesystem backend's `Query::matches` uses.
+            sql.push_str(" AND recipients LIKE ? ESCAPE '\\'");
+            values.push(Box::new(format!("%{}%", escape_like_pattern(recipient))));
+        }
+        if let Some(subject) = subject {
+            sql.push_str(" AND subject = ?");
+            values.push(Box::new(subject.to_owned()));
+        }
+        if let Some(since) = since {
+            sql.push_str(" AND timestamp_us >= ?");
+            values.push(Box::new(since as i64));
+        }
+        sql.push_str(" ORDER BY timestamp_us ASC");
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(&sql).map_err(Error::Sqlite)?;
+        let param_refs: Vec<&dyn rusqlite::ToSql> = values.iter().map(AsRef::as_ref).collect();
+
+        let rows = stmt
+            .query_map(param_refs.as_slice(), |row| row.get::<_, String>(0))
+            .map_err(Error::Sqlite)?;
+
+        let mut mails = Vec::new();
+        for blob in rows {
+            let blob = blob.map_err(Error::Sqlite)?;
+            mails.push(serde_json::from_str(&blob).map_err(Error::MailDeserialization)?);
+        }
+        Ok(mails)
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn.lock().expect("sqlite mail store mutex poisoned")
+    }
+}
+
+/// Escape `%`, `_` and `\` in `value` so it can be embedded in a `LIKE` pattern (with an
+/// `ESCAPE '\'` clause) and matched as a literal substring.
+fn escape_like_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+impl fmt::Debug for SqliteStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteStore").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CliOptions;
+
+    fn cli_options() -> CliOptions {
+        CliOptions {
+            debug: false,
+            ignore_dots: true,
+            inline_recipients: true,
+            options: Vec::new(),
+            dump: None,
+            option: Vec::new(),
+            sender: String::new(),
+            store_path: None,
+            serve_imap: None,
+        }
+    }
+
+    fn mail(raw_body: &str, recipients: &[&str]) -> Mail {
+        Mail::new(
+            cli_options(),
+            raw_body.as_bytes().to_vec(),
+            recipients.iter().map(|r| r.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn query_filters_by_sender_recipient_subject_and_since() {
+        let store = SqliteStore::open(":memory:").expect("open in-memory store");
+        store
+            .add(&mail(
+                "From: Marc <marc@example.com>\r\nSubject: hi\r\n\r\nbody",
+                &["a@example.com"],
+            ))
+            .unwrap();
+        store
+            .add(&mail(
+                "From: Santa <santa@example.com>\r\nSubject: bye\r\n\r\nbody",
+                &["b@example.com"],
+            ))
+            .unwrap();
+
+        let by_sender = store.query(Some("marc@example.com"), None, None, None).unwrap();
+        assert_eq!(by_sender.len(), 1);
+        assert_eq!(by_sender[0].recipients, vec!["a@example.com"]);
+
+        let by_recipient = store.query(None, Some("b@example"), None, None).unwrap();
+        assert_eq!(by_recipient.len(), 1);
+        assert_eq!(by_recipient[0].recipients, vec!["b@example.com"]);
+
+        let by_subject = store.query(None, None, Some("bye"), None).unwrap();
+        assert_eq!(by_subject.len(), 1);
+
+        let all = store.iter_mails().unwrap();
+        assert_eq!(all.len(), 2);
+
+        let latest = all.iter().map(|m| m.timestamp_us).max().unwrap();
+        let since_latest = store.query(None, None, None, Some(latest)).unwrap();
+        assert!(!since_latest.is_empty());
+        assert!(since_latest.iter().all(|m| m.timestamp_us >= latest));
+        let after_latest = store.query(None, None, None, Some(latest + 1)).unwrap();
+        assert!(after_latest.is_empty());
+    }
+
+    #[test]
+    fn recipient_query_does_not_treat_percent_or_underscore_as_wildcards() {
+        let store = SqliteStore::open(":memory:").expect("open in-memory store");
+        store
+            .add(&mail("Subject: s\r\n\r\nbody", &["100%done@example.com"]))
+            .unwrap();
+        store
+            .add(&mail("Subject: s\r\n\r\nbody", &["unrelated@example.com"]))
+            .unwrap();
+
+        // A literal `%` in the query string must not match every recipient.
+        let matches = store.query(None, Some("100%done"), None, None).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].recipients, vec!["100%done@example.com"]);
+
+        // An unrelated substring containing `_` must not accidentally match via wildcarding.
+        let no_matches = store.query(None, Some("100_done"), None, None).unwrap();
+        assert!(no_matches.is_empty());
+    }
+
+    #[test]
+    fn escape_like_pattern_escapes_wildcards_and_escape_char() {
+        assert_eq!(escape_like_pattern("a%b_c\\d"), "a\\%b\\_c\\\\d");
+        assert_eq!(escape_like_pattern("plain"), "plain");
+    }
+}
@@ -2,6 +2,83 @@ use displaydoc::Display;
 use std::{ffi, fs, io, path};
 use thiserror::Error;
 
+/// A single address parsed out of an address-list header field (`To`, `Cc`, `Bcc`, ...).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Address {
+    /// The display name, if any (e.g. `Santa Claus` in `Santa Claus <santa@example.com>`).
+    pub display_name: Option<String>,
+    /// The bare email address (e.g. `santa@example.com`).
+    pub address: String,
+}
+
+/// Parse a comma-separated address-list header value (as found in `To`, `Cc` or `Bcc`) into its
+/// individual addresses.
+///
+/// This is intentionally lenient rather than a full RFC 5322 address parser: entries are split
+/// on top-level commas (ignoring commas inside `"..."` quoted strings or `<...>` angle
+/// addresses), and each entry is read either as `Display Name <addr>` or a bare `addr`.
+pub fn parse_address_list(value: &str) -> Vec<Address> {
+    split_top_level_commas(value)
+        .into_iter()
+        .filter_map(|entry| parse_address(entry.trim()))
+        .collect()
+}
+
+/// Split on commas that are not inside a `"..."` quoted string or `<...>` angle address.
+fn split_top_level_commas(value: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut angle_depth = 0u32;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '<' if !in_quotes => angle_depth += 1,
+            '>' if !in_quotes && angle_depth > 0 => angle_depth -= 1,
+            ',' if !in_quotes && angle_depth == 0 => {
+                parts.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < value.len() {
+        parts.push(&value[start..]);
+    }
+
+    parts
+}
+
+/// Parse a single address-list entry (`Display Name <addr>` or a bare `addr`).
+fn parse_address(entry: &str) -> Option<Address> {
+    if entry.is_empty() {
+        return None;
+    }
+
+    if let Some(open) = entry.find('<') {
+        let close = entry.find('>')?;
+        let display_name = entry[..open].trim().trim_matches('"');
+        let address = entry[open + 1..close].trim();
+        if address.is_empty() {
+            return None;
+        }
+        Some(Address {
+            display_name: if display_name.is_empty() {
+                None
+            } else {
+                Some(display_name.to_owned())
+            },
+            address: address.to_owned(),
+        })
+    } else {
+        Some(Address {
+            display_name: None,
+            address: entry.to_owned(),
+        })
+    }
+}
+
 /// Error while iterating contents of directory.
 #[derive(Debug, Display, Error)]
 pub enum DirReadError {
@@ -0,0 +1,274 @@
+//! Blocking APIs for waiting on newly-arrived mail.
+//!
+//! Without these, integration tests have to poll `MailStore::iter_mails` in a sleep loop to
+//! notice that the application under test sent mail. `MailStore::wait_for_mail` and
+//! `MailStore::watch` block instead, waking up promptly via a filesystem notifier (inotify,
+//! kqueue, ...) on the filesystem backend, and fall back to a short poll interval otherwise
+//! (e.g. on the SQLite backend, or if the notifier could not be set up).
+
+use crate::{Mail, MailStore, Result};
+use nix::unistd::Pid;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashSet, VecDeque};
+use std::sync::mpsc;
+use std::time;
+
+/// How often to re-scan the store even if no filesystem notification arrived; bounds the
+/// latency of the SQLite-backend fallback and absorbs notifier events we fail to set up.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(200);
+
+/// A unique identifier for a `Mail`, used to tell which mails have already been observed.
+///
+/// `disambiguator` must be included: it is exactly what distinguishes two mails created by the
+/// same process within the same microsecond (see `Mail::disambiguator`), so dropping it would
+/// collapse such mails onto a single `seen` entry and silently lose the second one.
+type MailId = (u128, i32, i32, u64);
+
+fn mail_id(mail: &Mail) -> MailId {
+    fn raw(pid: &Pid) -> i32 {
+        pid.as_raw()
+    }
+    (
+        mail.timestamp_us,
+        raw(&mail.ppid),
+        raw(&mail.pid),
+        mail.disambiguator,
+    )
+}
+
+/// Best-effort filesystem notifier: `None` if the store isn't filesystem-backed or the notifier
+/// could not be started, in which case callers fall back to polling alone.
+fn start_watcher(store: &MailStore) -> Option<(RecommendedWatcher, mpsc::Receiver<()>)> {
+    let root = store.fs_root()?;
+    let (tx, rx) = mpsc::channel();
+
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if res.is_ok() {
+            // The receiver only cares that *something* changed; `wait_for_mail`/`watch`
+            // re-scan the store themselves rather than trying to interpret the event.
+            let _ = tx.send(());
+        }
+    })
+    .ok()?;
+    watcher.watch(root, RecursiveMode::NonRecursive).ok()?;
+
+    Some((watcher, rx))
+}
+
+impl MailStore {
+    /// Block until a new mail is captured, or `timeout` elapses.
+    ///
+    /// Only mail captured *after* this call is considered; anything already in the store when
+    /// called is ignored. Returns `Ok(None)` on timeout.
+    pub fn wait_for_mail(&self, timeout: time::Duration) -> Result<Option<Mail>> {
+        self.wait_for_mail_matching(timeout, |_| true)
+    }
+
+    /// Like `wait_for_mail`, but only returns a mail once `predicate` accepts it, letting a test
+    /// block precisely until e.g. a mail to a given recipient arrives.
+    pub fn wait_for_mail_matching<P>(
+        &self,
+        timeout: time::Duration,
+        mut predicate: P,
+    ) -> Result<Option<Mail>>
+    where
+        P: FnMut(&Mail) -> bool,
+    {
+        let mut seen: HashSet<MailId> = self
+            .iter_mails()
+            .collect::<Result<Vec<_>>>()?
+            .iter()
+            .map(mail_id)
+            .collect();
+
+        let watcher = start_watcher(self);
+        let deadline = time::Instant::now() + timeout;
+
+        loop {
+            for mail in self.iter_mails() {
+                let mail = mail?;
+                if seen.insert(mail_id(&mail)) && predicate(&mail) {
+                    return Ok(Some(mail));
+                }
+            }
+
+            let remaining = deadline.saturating_duration_since(time::Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+
+            match &watcher {
+                Some((_watcher, rx)) => {
+                    let _ = rx.recv_timeout(remaining.min(POLL_INTERVAL));
+                }
+                None => std::thread::sleep(remaining.min(POLL_INTERVAL)),
+            }
+        }
+    }
+
+    /// Return an iterator that blocks forever, yielding each new mail in timestamp order as it
+    /// is captured.
+    ///
+    /// Like `wait_for_mail`, mail already present in the store when `watch` is called is not
+    /// yielded.
+    pub fn watch(&self) -> Watch<'_> {
+        let seen = self
+            .iter_mails()
+            .filter_map(|r| r.ok())
+            .map(|mail| mail_id(&mail))
+            .collect();
+
+        Watch {
+            store: self,
+            seen,
+            pending: VecDeque::new(),
+            watcher: start_watcher(self),
+        }
+    }
+}
+
+/// A blocking iterator over newly-arrived mail; see `MailStore::watch`.
+pub struct Watch<'a> {
+    store: &'a MailStore,
+    seen: HashSet<MailId>,
+    pending: VecDeque<Mail>,
+    watcher: Option<(RecommendedWatcher, mpsc::Receiver<()>)>,
+}
+
+impl<'a> Watch<'a> {
+    /// Re-scan the store, queueing up any mail not yet seen.
+    fn scan(&mut self) -> Result<()> {
+        for mail in self.store.iter_mails() {
+            let mail = mail?;
+            if self.seen.insert(mail_id(&mail)) {
+                self.pending.push_back(mail);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Watch<'a> {
+    type Item = Result<Mail>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(mail) = self.pending.pop_front() {
+                return Some(Ok(mail));
+            }
+
+            if let Err(e) = self.scan() {
+                return Some(Err(e));
+            }
+            if let Some(mail) = self.pending.pop_front() {
+                return Some(Ok(mail));
+            }
+
+            match &self.watcher {
+                Some((_watcher, rx)) => {
+                    let _ = rx.recv_timeout(POLL_INTERVAL);
+                }
+                None => std::thread::sleep(POLL_INTERVAL),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CliOptions;
+
+    fn cli_options() -> CliOptions {
+        CliOptions {
+            debug: false,
+            ignore_dots: true,
+            inline_recipients: true,
+            options: Vec::new(),
+            dump: None,
+            option: Vec::new(),
+            sender: String::new(),
+            store_path: None,
+            serve_imap: None,
+        }
+    }
+
+    fn mail() -> Mail {
+        Mail::new(cli_options(), b"Subject: s\r\n\r\nbody".to_vec(), Vec::new())
+    }
+
+    #[test]
+    fn mail_id_differs_for_mails_created_in_the_same_microsecond() {
+        // `Mail::new` reads the real clock, so two calls back-to-back may well land on the same
+        // `timestamp_us` with identical `pid`/`ppid`; only the disambiguator can tell them apart.
+        let a = mail();
+        let b = mail();
+        assert_ne!(a.disambiguator, b.disambiguator);
+        assert_ne!(mail_id(&a), mail_id(&b));
+    }
+
+    #[test]
+    fn mail_id_is_stable_for_the_same_mail() {
+        let m = mail();
+        assert_eq!(mail_id(&m), mail_id(&m));
+    }
+
+    #[test]
+    fn wait_for_mail_matching_ignores_mail_already_in_the_store() {
+        let dir = tempfile_dir();
+        let store = MailStore::with_root(dir.clone());
+        store.add(&mail()).unwrap();
+
+        let result = store
+            .wait_for_mail_matching(time::Duration::from_millis(50), |_| true)
+            .unwrap();
+        assert!(result.is_none());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn wait_for_mail_matching_finds_mail_added_after_the_call_starts() {
+        let dir = tempfile_dir();
+        let store = MailStore::with_root(dir.clone());
+
+        let mail_to_add = mail();
+        let expected_id = mail_id(&mail_to_add);
+        store.add(&mail_to_add).unwrap();
+
+        // Mail already present before `wait_for_mail` is called is not yielded, so the mail
+        // above only seeds `seen`; wait for a second mail that arrives only after the call starts.
+        let second = mail();
+        let second_id = mail_id(&second);
+        assert_ne!(expected_id, second_id);
+
+        let store_path = dir.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(time::Duration::from_millis(20));
+            let store = MailStore::with_root(store_path.clone());
+            store.add(&second).unwrap();
+        });
+
+        let result = store
+            .wait_for_mail_matching(time::Duration::from_millis(500), |m| {
+                mail_id(m) == second_id
+            })
+            .unwrap();
+        handle.join().unwrap();
+
+        assert_eq!(result.map(|m| mail_id(&m)), Some(second_id));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    fn tempfile_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "trapmail-watch-test-{}-{}",
+            std::process::id(),
+            MAIL_COUNTER_FOR_TESTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    static MAIL_COUNTER_FOR_TESTS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+}